@@ -0,0 +1,272 @@
+// Copyright 2021  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Physical memory regions and the frame allocator built from them.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// The kind of physical memory a [`Region`] describes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Type {
+    RAM,
+    Reserved,
+    ACPI,
+    NonVolatile,
+    Defective,
+    Loader,
+    Module,
+}
+
+/// A half-open `[start, end)` range of physical memory of a single
+/// [`Type`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Region {
+    pub(crate) start: u64,
+    pub(crate) end: u64,
+    pub(crate) typ: Type,
+}
+
+impl Region {
+    pub(crate) fn cmp(a: &Region, b: &Region) -> core::cmp::Ordering {
+        (a.start, a.end).cmp(&(b.start, b.end))
+    }
+}
+
+pub(crate) const FRAME_SIZE: u64 = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Error {
+    OutOfMemory,
+    Unaligned,
+    OutOfRange,
+    NotAllocated,
+}
+
+/// A two-level bitmap allocator over 4 KiB physical frames.
+///
+/// The first level (`bitmap`) has one bit per frame: set means
+/// allocated. The second level (`summary`) has one bit per bitmap
+/// word and is set whenever that word has at least one free frame, so
+/// a search for a free frame can skip fully-allocated words 64 at a
+/// time instead of probing every bit.
+pub(crate) struct FrameAllocator {
+    base: u64,
+    frame_count: usize,
+    bitmap: Vec<u64>,
+    summary: Vec<bool>,
+}
+
+impl FrameAllocator {
+    /// Build an allocator spanning the `Type::RAM` regions in
+    /// `regions`. Every other region type (the loader image, modules,
+    /// ACPI tables, reserved and non-volatile memory, and defective
+    /// ranges) is treated as permanently allocated, so it is never
+    /// handed out.
+    pub(crate) fn new(regions: &[Region]) -> FrameAllocator {
+        let ram = regions.iter().filter(|r| r.typ == Type::RAM);
+        let base = ram.clone().map(|r| r.start).min().unwrap_or(0);
+        let end = ram.clone().map(|r| r.end).max().unwrap_or(base);
+        let frame_count = ((end.saturating_sub(base)) / FRAME_SIZE) as usize;
+        let words = frame_count.div_ceil(64);
+
+        // Start fully allocated, then free the RAM ranges frame by frame.
+        let mut allocator =
+            FrameAllocator { base, frame_count, bitmap: vec![u64::MAX; words], summary: vec![false; words] };
+        for region in regions.iter().filter(|r| r.typ == Type::RAM) {
+            allocator.mark(region.start, region.end, false);
+        }
+        for region in regions.iter().filter(|r| r.typ != Type::RAM) {
+            allocator.mark(region.start, region.end, true);
+        }
+        allocator
+    }
+
+    /// Permanently remove `region` from the free pool, e.g. for memory
+    /// discovered to be in use after the allocator was built.
+    pub(crate) fn reserve(&mut self, region: &Region) {
+        self.mark(region.start, region.end, true);
+    }
+
+    fn frame_of(&self, addr: u64) -> Option<usize> {
+        if addr < self.base || addr % FRAME_SIZE != 0 {
+            return None;
+        }
+        let frame = ((addr - self.base) / FRAME_SIZE) as usize;
+        (frame < self.frame_count).then_some(frame)
+    }
+
+    fn mark(&mut self, start: u64, end: u64, allocated: bool) {
+        let start = start.max(self.base);
+        let end = end.min(self.base + self.frame_count as u64 * FRAME_SIZE);
+        let mut frame = (start.saturating_sub(self.base)) / FRAME_SIZE;
+        let last = (end.saturating_sub(self.base)).div_ceil(FRAME_SIZE);
+        while frame < last {
+            self.set(frame as usize, allocated);
+            frame += 1;
+        }
+    }
+
+    fn set(&mut self, frame: usize, allocated: bool) {
+        let word = frame / 64;
+        let bit = 1u64 << (frame % 64);
+        if allocated {
+            self.bitmap[word] |= bit;
+        } else {
+            self.bitmap[word] &= !bit;
+        }
+        self.summary[word] = self.bitmap[word] != u64::MAX;
+    }
+
+    fn is_free(&self, frame: usize) -> bool {
+        self.bitmap[frame / 64] & (1u64 << (frame % 64)) == 0
+    }
+
+    /// Allocate a single free frame, returning its physical address.
+    pub(crate) fn alloc_frame(&mut self) -> Option<u64> {
+        let word = self.summary.iter().position(|&free| free)?;
+        let bit = (!self.bitmap[word]).trailing_zeros() as usize;
+        let frame = word * 64 + bit;
+        self.set(frame, true);
+        Some(self.base + frame as u64 * FRAME_SIZE)
+    }
+
+    /// Allocate `n` contiguous free frames, returning the physical
+    /// address of the first one.
+    pub(crate) fn alloc_contiguous(&mut self, n: usize) -> Option<u64> {
+        if n == 0 {
+            return None;
+        }
+
+        let mut run_start = 0;
+        let mut run_len = 0;
+        for frame in 0..self.frame_count {
+            if self.is_free(frame) {
+                if run_len == 0 {
+                    run_start = frame;
+                }
+                run_len += 1;
+                if run_len == n {
+                    for f in run_start..run_start + n {
+                        self.set(f, true);
+                    }
+                    return Some(self.base + run_start as u64 * FRAME_SIZE);
+                }
+            } else {
+                run_len = 0;
+            }
+        }
+        None
+    }
+
+    /// Free a single frame previously returned by [`Self::alloc_frame`]
+    /// or [`Self::alloc_contiguous`].
+    pub(crate) fn free_frame(&mut self, addr: u64) -> Result<(), Error> {
+        if addr % FRAME_SIZE != 0 {
+            return Err(Error::Unaligned);
+        }
+        let frame = self.frame_of(addr).ok_or(Error::OutOfRange)?;
+        if self.is_free(frame) {
+            return Err(Error::NotAllocated);
+        }
+        self.set(frame, false);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ram(start: u64, end: u64) -> Region {
+        Region { start, end, typ: Type::RAM }
+    }
+
+    fn reserved(start: u64, end: u64) -> Region {
+        Region { start, end, typ: Type::Reserved }
+    }
+
+    #[test]
+    fn single_region_allocates_every_frame() {
+        let regions = [ram(0, 4 * FRAME_SIZE)];
+        let mut allocator = FrameAllocator::new(&regions);
+
+        let mut frames = Vec::new();
+        for _ in 0..4 {
+            frames.push(allocator.alloc_frame().expect("frame available"));
+        }
+        assert_eq!(allocator.alloc_frame(), None);
+
+        frames.sort_unstable();
+        assert_eq!(frames, vec![0, FRAME_SIZE, 2 * FRAME_SIZE, 3 * FRAME_SIZE]);
+    }
+
+    #[test]
+    fn singleton_region_yields_exactly_one_frame() {
+        let regions = [ram(0x1000, 0x2000)];
+        let mut allocator = FrameAllocator::new(&regions);
+
+        assert_eq!(allocator.alloc_frame(), Some(0x1000));
+        assert_eq!(allocator.alloc_frame(), None);
+    }
+
+    #[test]
+    fn adjacent_regions_allocate_contiguously() {
+        let regions = [ram(0, 2 * FRAME_SIZE), ram(2 * FRAME_SIZE, 4 * FRAME_SIZE)];
+        let mut allocator = FrameAllocator::new(&regions);
+
+        let addr = allocator.alloc_contiguous(4).expect("4 contiguous frames");
+        assert_eq!(addr, 0);
+        assert_eq!(allocator.alloc_frame(), None);
+    }
+
+    #[test]
+    fn non_ram_regions_are_never_handed_out() {
+        let regions = [ram(0, 4 * FRAME_SIZE), reserved(FRAME_SIZE, 2 * FRAME_SIZE)];
+        let mut allocator = FrameAllocator::new(&regions);
+
+        let mut frames = Vec::new();
+        while let Some(frame) = allocator.alloc_frame() {
+            frames.push(frame);
+        }
+        frames.sort_unstable();
+        assert_eq!(frames, vec![0, 2 * FRAME_SIZE, 3 * FRAME_SIZE]);
+    }
+
+    #[test]
+    fn free_then_reallocate() {
+        let regions = [ram(0, FRAME_SIZE)];
+        let mut allocator = FrameAllocator::new(&regions);
+
+        let addr = allocator.alloc_frame().unwrap();
+        assert_eq!(allocator.alloc_frame(), None);
+
+        allocator.free_frame(addr).unwrap();
+        assert_eq!(allocator.alloc_frame(), Some(addr));
+    }
+
+    #[test]
+    fn free_unallocated_frame_is_an_error() {
+        let regions = [ram(0, FRAME_SIZE)];
+        let mut allocator = FrameAllocator::new(&regions);
+        assert_eq!(allocator.free_frame(0), Err(Error::NotAllocated));
+    }
+
+    #[test]
+    fn reserve_removes_frames_from_the_free_pool() {
+        let regions = [ram(0, 4 * FRAME_SIZE)];
+        let mut allocator = FrameAllocator::new(&regions);
+        allocator.reserve(&Region { start: 0, end: 2 * FRAME_SIZE, typ: Type::Loader });
+
+        let mut frames = Vec::new();
+        while let Some(frame) = allocator.alloc_frame() {
+            frames.push(frame);
+        }
+        frames.sort_unstable();
+        assert_eq!(frames, vec![2 * FRAME_SIZE, 3 * FRAME_SIZE]);
+    }
+}
@@ -0,0 +1,432 @@
+// Copyright 2021  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Dependency resolution and relocation for inter-module linking.
+//!
+//! A Multiboot module loaded by [`super::elf`] may declare `DT_NEEDED`
+//! dependencies on other modules rather than being self-contained.
+//! This parses the `PT_DYNAMIC` segment of each module, orders the
+//! modules by dependency, and applies the handful of relocation types
+//! used by simple freestanding shared objects once every dependency
+//! has been placed.
+
+use super::elf;
+use super::multiboot1::MultibootModule;
+use crate::theon;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const DT_NULL: i64 = 0;
+const DT_NEEDED: i64 = 1;
+const DT_PLTRELSZ: i64 = 2;
+const DT_HASH: i64 = 4;
+const DT_STRTAB: i64 = 5;
+const DT_SYMTAB: i64 = 6;
+const DT_RELA: i64 = 7;
+const DT_RELASZ: i64 = 8;
+const DT_STRSZ: i64 = 10;
+const DT_JMPREL: i64 = 23;
+
+const R_X86_64_RELATIVE: u32 = 8;
+const R_X86_64_GLOB_DAT: u32 = 6;
+const R_X86_64_JUMP_SLOT: u32 = 7;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Dyn {
+    tag: i64,
+    val: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Sym {
+    name: u32,
+    info: u8,
+    other: u8,
+    shndx: u16,
+    value: u64,
+    size: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Rela {
+    offset: u64,
+    info: u64,
+    addend: i64,
+}
+
+impl Rela {
+    fn sym(&self) -> u32 {
+        (self.info >> 32) as u32
+    }
+
+    fn kind(&self) -> u32 {
+        (self.info & 0xffff_ffff) as u32
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Error {
+    NoDynamicSegment,
+    Malformed,
+}
+
+/// The portions of `PT_DYNAMIC` needed to resolve and bind a module
+/// against its dependencies.
+struct Dynamic {
+    needed: Vec<&'static str>,
+    strtab: &'static [u8],
+    symtab: &'static [Sym],
+    rela: &'static [Rela],
+    jmprel: &'static [Rela],
+}
+
+fn str_at(strtab: &'static [u8], offset: usize) -> Result<&'static str, Error> {
+    let bytes = strtab.get(offset..).ok_or(Error::Malformed)?;
+    let len = bytes.iter().position(|&b| b == 0).ok_or(Error::Malformed)?;
+    core::str::from_utf8(&bytes[..len]).map_err(|_| Error::Malformed)
+}
+
+/// Does `[addr, addr + len)` fall entirely within one of the module's
+/// own `PT_LOAD` segments? Every `DT_*` address below is meant to
+/// point somewhere inside the module that declares it, so this is the
+/// bound every one of them is checked against before it is
+/// dereferenced.
+fn within_segments(addr: u64, len: u64, segments: &[elf::Segment]) -> bool {
+    let Some(end) = addr.checked_add(len) else { return false };
+    segments.iter().any(|s| addr >= s.vaddr && end <= s.vaddr + s.memsz)
+}
+
+/// Validate `[addr, addr + len)`, a `DT_*` address and size, against
+/// `dest_limit` and the module's own segments, so that the caller may
+/// pass it to [`core::slice::from_raw_parts`].
+fn checked_range(addr: u64, len: u64, dest_limit: u64, segments: &[elf::Segment]) -> Result<(), Error> {
+    let end = addr.checked_add(len).ok_or(Error::Malformed)?;
+    if end > dest_limit || !within_segments(addr, len, segments) {
+        return Err(Error::Malformed);
+    }
+    Ok(())
+}
+
+/// Parse the `PT_DYNAMIC` segment of an already-loaded module.
+///
+/// Every address and size taken from the `PT_DYNAMIC` table is
+/// validated against `dest_limit` and the module's own segments (see
+/// [`checked_range`]) before it is used to build a slice, so a
+/// malformed or hostile module can't make this read — or, via
+/// `bind`'s relocations, write — outside of memory it actually owns.
+///
+/// # Safety
+///
+/// `loaded` must have been produced by [`elf::load`] against a module
+/// whose segments are still mapped at their recorded virtual
+/// addresses.
+unsafe fn parse(loaded: &elf::Loaded, dest_limit: u64) -> Result<Dynamic, Error> {
+    let segments = &loaded.segments;
+    let (vaddr, memsz) = loaded.dynamic.ok_or(Error::NoDynamicSegment)?;
+    checked_range(vaddr, memsz, dest_limit, segments)?;
+    let count = memsz as usize / core::mem::size_of::<Dyn>();
+    let dyns =
+        unsafe { core::slice::from_raw_parts(theon::VZERO.add(vaddr as usize).cast::<Dyn>(), count) };
+
+    let mut needed_offsets = Vec::new();
+    let (mut strtab_addr, mut strsz) = (None, None);
+    let (mut symtab_addr, mut hash_addr) = (None, None);
+    let (mut rela_addr, mut relasz) = (None, None);
+    let (mut jmprel_addr, mut pltrelsz) = (None, None);
+
+    for d in dyns {
+        match d.tag {
+            DT_NULL => break,
+            DT_NEEDED => needed_offsets.push(d.val),
+            DT_STRTAB => strtab_addr = Some(d.val),
+            DT_STRSZ => strsz = Some(d.val),
+            DT_SYMTAB => symtab_addr = Some(d.val),
+            DT_HASH => hash_addr = Some(d.val),
+            DT_RELA => rela_addr = Some(d.val),
+            DT_RELASZ => relasz = Some(d.val),
+            DT_JMPREL => jmprel_addr = Some(d.val),
+            DT_PLTRELSZ => pltrelsz = Some(d.val),
+            _ => {}
+        }
+    }
+
+    let strtab_addr = strtab_addr.ok_or(Error::Malformed)?;
+    let strsz = strsz.ok_or(Error::Malformed)?;
+    checked_range(strtab_addr, strsz, dest_limit, segments)?;
+    let strtab =
+        unsafe { core::slice::from_raw_parts(theon::VZERO.add(strtab_addr as usize), strsz as usize) };
+
+    let needed = needed_offsets
+        .into_iter()
+        .map(|off| str_at(strtab, off as usize))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    // The symbol count isn't recorded directly; derive it from the
+    // legacy `DT_HASH` table's `nchain`, which equals the number of
+    // entries in `DT_SYMTAB`.
+    let symtab = match (symtab_addr, hash_addr) {
+        (Some(sym_addr), Some(hash_addr)) => unsafe {
+            checked_range(hash_addr, 8, dest_limit, segments)?;
+            let header =
+                core::slice::from_raw_parts(theon::VZERO.add(hash_addr as usize).cast::<u32>(), 2);
+            let nchain = header[1] as usize;
+            let symtab_len = (nchain as u64)
+                .checked_mul(core::mem::size_of::<Sym>() as u64)
+                .ok_or(Error::Malformed)?;
+            checked_range(sym_addr, symtab_len, dest_limit, segments)?;
+            core::slice::from_raw_parts(theon::VZERO.add(sym_addr as usize).cast::<Sym>(), nchain)
+        },
+        _ => &[],
+    };
+
+    let rela = match (rela_addr, relasz) {
+        (Some(addr), Some(size)) => unsafe {
+            checked_range(addr, size, dest_limit, segments)?;
+            core::slice::from_raw_parts(
+                theon::VZERO.add(addr as usize).cast::<Rela>(),
+                size as usize / core::mem::size_of::<Rela>(),
+            )
+        },
+        _ => &[],
+    };
+
+    let jmprel = match (jmprel_addr, pltrelsz) {
+        (Some(addr), Some(size)) => unsafe {
+            checked_range(addr, size, dest_limit, segments)?;
+            core::slice::from_raw_parts(
+                theon::VZERO.add(addr as usize).cast::<Rela>(),
+                size as usize / core::mem::size_of::<Rela>(),
+            )
+        },
+        _ => &[],
+    };
+
+    Ok(Dynamic { needed, strtab, symtab, rela, jmprel })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum LinkError {
+    MissingDependency,
+    DependencyCycle,
+    Elf(elf::Error),
+    Dynamic(Error),
+}
+
+impl From<elf::Error> for LinkError {
+    fn from(err: elf::Error) -> LinkError {
+        LinkError::Elf(err)
+    }
+}
+
+impl From<Error> for LinkError {
+    fn from(err: Error) -> LinkError {
+        LinkError::Dynamic(err)
+    }
+}
+
+/// A module that has been loaded and, if it declared any, had its
+/// dependencies bound.
+pub(crate) struct Linked<'a> {
+    pub(crate) name: Option<&'a str>,
+    pub(crate) loaded: elf::Loaded,
+}
+
+struct Unit<'a> {
+    name: Option<&'a str>,
+    loaded: elf::Loaded,
+    dynamic: Option<Dynamic>,
+}
+
+/// Load every module in `modules`, resolve their `DT_NEEDED`
+/// dependencies against one another by name, and apply relocations in
+/// dependency order.
+///
+/// `dest_limit` bounds where a module's segments may be mapped; see
+/// [`elf::load`].
+pub(crate) fn link_modules<'a>(
+    modules: &'a [MultibootModule<'a>],
+    dest_limit: u64,
+) -> Result<Vec<Linked<'a>>, LinkError> {
+    let mut units = Vec::with_capacity(modules.len());
+    for module in modules {
+        let loaded = module.load(dest_limit)?;
+        super::reserve_loaded(&loaded);
+        let dynamic =
+            if loaded.dynamic.is_some() { Some(unsafe { parse(&loaded, dest_limit)? }) } else { None };
+        units.push(Unit { name: module.name, loaded, dynamic });
+    }
+
+    let order = topo_sort(&units)?;
+    for &i in &order {
+        bind(i, &units)?;
+    }
+
+    Ok(units.into_iter().map(|u| Linked { name: u.name, loaded: u.loaded }).collect())
+}
+
+/// Kahn's algorithm over the `DT_NEEDED` edges: a dependency must be
+/// bound before any module that needs it.
+fn topo_sort(units: &[Unit<'_>]) -> Result<Vec<usize>, LinkError> {
+    let n = units.len();
+    let mut indegree = vec![0usize; n];
+    let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); n];
+
+    for (i, unit) in units.iter().enumerate() {
+        let Some(dynamic) = &unit.dynamic else { continue };
+        for name in &dynamic.needed {
+            let dep = units.iter().position(|u| u.name == Some(*name)).ok_or(LinkError::MissingDependency)?;
+            dependents[dep].push(i);
+            indegree[i] += 1;
+        }
+    }
+
+    let mut ready: Vec<usize> = (0..n).filter(|&i| indegree[i] == 0).collect();
+    let mut order = Vec::with_capacity(n);
+    while let Some(i) = ready.pop() {
+        order.push(i);
+        for &next in &dependents[i] {
+            indegree[next] -= 1;
+            if indegree[next] == 0 {
+                ready.push(next);
+            }
+        }
+    }
+
+    if order.len() != n {
+        return Err(LinkError::DependencyCycle);
+    }
+    Ok(order)
+}
+
+fn bind(i: usize, units: &[Unit<'_>]) -> Result<(), LinkError> {
+    let Some(dynamic) = &units[i].dynamic else { return Ok(()) };
+    let segments = &units[i].loaded.segments;
+
+    for rela in dynamic.rela.iter().chain(dynamic.jmprel.iter()) {
+        // The write target must land inside this module's own mapped
+        // segments, the same bound `parse` holds every DT_* address to.
+        if !within_segments(rela.offset, core::mem::size_of::<u64>() as u64, segments) {
+            return Err(LinkError::Dynamic(Error::Malformed));
+        }
+        let target = unsafe { theon::VZERO.add(rela.offset as usize).cast_mut().cast::<u64>() };
+        match rela.kind() {
+            R_X86_64_RELATIVE => unsafe { *target = rela.addend as u64 },
+            R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT => {
+                let sym = dynamic.symtab.get(rela.sym() as usize).ok_or(LinkError::MissingDependency)?;
+                let name = str_at(dynamic.strtab, sym.name as usize)?;
+                let value = resolve(name, units).ok_or(LinkError::MissingDependency)?;
+                unsafe { *target = value };
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Look up a symbol by name among the exported (defined, non-zero
+/// `shndx`) dynamic symbols of every module.
+fn resolve(name: &str, units: &[Unit<'_>]) -> Option<u64> {
+    for unit in units {
+        let Some(dynamic) = &unit.dynamic else { continue };
+        for sym in dynamic.symtab {
+            if sym.shndx == 0 {
+                continue;
+            }
+            if str_at(dynamic.strtab, sym.name as usize) == Ok(name) {
+                return Some(sym.value);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit(name: Option<&'static str>, dynamic: Option<Dynamic>) -> Unit<'static> {
+        Unit { name, loaded: elf::Loaded { entry: 0, segments: Vec::new(), dynamic: None, build_id: None }, dynamic }
+    }
+
+    fn needing(name: &'static str, needed: Vec<&'static str>) -> Unit<'static> {
+        unit(Some(name), Some(Dynamic { needed, strtab: &[], symtab: &[], rela: &[], jmprel: &[] }))
+    }
+
+    #[test]
+    fn topo_sort_orders_a_dependency_before_its_dependent() {
+        let units = [needing("a", Vec::new()), needing("b", vec!["a"])];
+        let order = topo_sort(&units).unwrap();
+        let pos = |name| order.iter().position(|&i| units[i].name == Some(name)).unwrap();
+        assert!(pos("a") < pos("b"));
+    }
+
+    #[test]
+    fn topo_sort_rejects_a_missing_dependency() {
+        let units = [needing("b", vec!["missing"])];
+        assert_eq!(topo_sort(&units), Err(LinkError::MissingDependency));
+    }
+
+    #[test]
+    fn topo_sort_rejects_a_cycle() {
+        let units = [needing("a", vec!["b"]), needing("b", vec!["a"])];
+        assert_eq!(topo_sort(&units), Err(LinkError::DependencyCycle));
+    }
+
+    fn segment(vaddr: u64, memsz: u64) -> elf::Segment {
+        elf::Segment { vaddr, memsz, perms: elf::Permissions { read: true, write: true, execute: false } }
+    }
+
+    #[test]
+    fn resolve_finds_an_exported_symbol_in_a_dependency() {
+        static STRTAB: [u8; 4] = *b"foo\0";
+        static SYMTAB: [Sym; 1] = [Sym { name: 0, info: 0, other: 0, shndx: 1, value: 0x1000, size: 0 }];
+        let exporter =
+            unit(Some("a"), Some(Dynamic { needed: Vec::new(), strtab: &STRTAB, symtab: &SYMTAB, rela: &[], jmprel: &[] }));
+        assert_eq!(resolve("foo", &[exporter]), Some(0x1000));
+    }
+
+    #[test]
+    fn resolve_ignores_undefined_symbols() {
+        static STRTAB: [u8; 4] = *b"foo\0";
+        static SYMTAB: [Sym; 1] = [Sym { name: 0, info: 0, other: 0, shndx: 0, value: 0x1000, size: 0 }];
+        let exporter =
+            unit(Some("a"), Some(Dynamic { needed: Vec::new(), strtab: &STRTAB, symtab: &SYMTAB, rela: &[], jmprel: &[] }));
+        assert_eq!(resolve("foo", &[exporter]), None);
+    }
+
+    #[test]
+    fn bind_rejects_a_relocation_target_outside_the_modules_segments() {
+        static RELA: [Rela; 1] = [Rela { offset: 0x5000, info: 0, addend: 0 }];
+        let mut target = unit(
+            Some("a"),
+            Some(Dynamic { needed: Vec::new(), strtab: &[], symtab: &[], rela: &RELA, jmprel: &[] }),
+        );
+        target.loaded.segments.push(segment(0x1000, 0x100));
+
+        let units = [target];
+        assert_eq!(bind(0, &units), Err(LinkError::Dynamic(Error::Malformed)));
+    }
+
+    #[test]
+    fn bind_reports_a_missing_dependency_for_an_unresolved_glob_dat() {
+        static STRTAB: [u8; 4] = *b"bar\0";
+        static SYMTAB: [Sym; 1] = [Sym { name: 0, info: 0, other: 0, shndx: 0, value: 0, size: 0 }];
+        static RELA: [Rela; 1] = [Rela { offset: 0x1000, info: (0u64 << 32) | R_X86_64_GLOB_DAT as u64, addend: 0 }];
+
+        let mut needer = unit(
+            Some("needer"),
+            Some(Dynamic { needed: Vec::new(), strtab: &STRTAB, symtab: &SYMTAB, rela: &RELA, jmprel: &[] }),
+        );
+        needer.loaded.segments.push(segment(0x1000, 0x100));
+
+        let units = [needer];
+        assert_eq!(bind(0, &units), Err(LinkError::MissingDependency));
+    }
+}
@@ -0,0 +1,350 @@
+// Copyright 2021  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A minimal ELF64 loader for Multiboot-supplied modules.
+//!
+//! This only understands enough of the ELF64 format to map the
+//! `PT_LOAD` segments of a module image into place and recover its
+//! entry point; it is not a general-purpose ELF library.
+
+use crate::theon;
+use alloc::vec::Vec;
+
+const ELFMAG: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+pub(crate) const PT_NOTE: u32 = 4;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const NT_GNU_BUILD_ID: u32 = 3;
+
+/// The length of a `.note.gnu.build-id` descriptor, as produced by the
+/// default `ld.bfd`/`lld` SHA-1 build-id style.
+pub(crate) const BUILD_ID_LEN: usize = 20;
+
+/// The R/W/X permissions requested by a segment's `p_flags`.
+///
+/// These are recorded for later use but not yet enforced: there is no
+/// page-table/mapping subsystem in this tree yet to apply them to, so
+/// today every segment is mapped with whatever permissions the
+/// identity map already has.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Permissions {
+    pub(crate) read: bool,
+    pub(crate) write: bool,
+    pub(crate) execute: bool,
+}
+
+impl Permissions {
+    fn from_flags(flags: u32) -> Permissions {
+        Permissions { read: flags & PF_R != 0, write: flags & PF_W != 0, execute: flags & PF_X != 0 }
+    }
+}
+
+/// A single `PT_LOAD` segment that has been copied into place.
+#[derive(Debug)]
+pub(crate) struct Segment {
+    pub(crate) vaddr: u64,
+    pub(crate) memsz: u64,
+    /// Requested, not enforced; see [`Permissions`].
+    pub(crate) perms: Permissions,
+}
+
+/// The result of loading an ELF64 module: where to start executing it
+/// and the segments that make up its address space.
+pub(crate) struct Loaded {
+    pub(crate) entry: u64,
+    pub(crate) segments: Vec<Segment>,
+    /// The `(vaddr, memsz)` of the `PT_DYNAMIC` segment, if the module
+    /// has one; consumed by [`super::dynamic`] to resolve inter-module
+    /// dependencies.
+    pub(crate) dynamic: Option<(u64, u64)>,
+    /// The `NT_GNU_BUILD_ID` descriptor from `.note.gnu.build-id`, if
+    /// present and of the expected length.
+    pub(crate) build_id: Option<[u8; BUILD_ID_LEN]>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Error {
+    TooShort,
+    BadMagic,
+    NotElf64,
+    NotLittleEndian,
+    WrongMachine,
+    BadProgramHeaders,
+    SegmentOutOfBounds,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Ehdr {
+    e_ident: [u8; 16],
+    e_type: u16,
+    e_machine: u16,
+    e_version: u32,
+    e_entry: u64,
+    e_phoff: u64,
+    e_shoff: u64,
+    e_flags: u32,
+    e_ehsize: u16,
+    e_phentsize: u16,
+    e_phnum: u16,
+    e_shentsize: u16,
+    e_shnum: u16,
+    e_shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct Phdr {
+    pub(crate) p_type: u32,
+    pub(crate) p_flags: u32,
+    pub(crate) p_offset: u64,
+    pub(crate) p_vaddr: u64,
+    pub(crate) p_paddr: u64,
+    pub(crate) p_filesz: u64,
+    pub(crate) p_memsz: u64,
+    pub(crate) p_align: u64,
+}
+
+fn read_ehdr(bytes: &[u8]) -> Result<Ehdr, Error> {
+    if bytes.len() < core::mem::size_of::<Ehdr>() {
+        return Err(Error::TooShort);
+    }
+    if bytes[0..4] != ELFMAG {
+        return Err(Error::BadMagic);
+    }
+    if bytes[4] != ELFCLASS64 {
+        return Err(Error::NotElf64);
+    }
+    if bytes[5] != ELFDATA2LSB {
+        return Err(Error::NotLittleEndian);
+    }
+    let ehdr = unsafe { bytes.as_ptr().cast::<Ehdr>().read_unaligned() };
+    if ehdr.e_machine != EM_X86_64 {
+        return Err(Error::WrongMachine);
+    }
+    Ok(ehdr)
+}
+
+pub(crate) fn program_headers<'a>(bytes: &'a [u8], ehdr_bytes: &[u8]) -> Result<&'a [Phdr], Error> {
+    let ehdr = read_ehdr(ehdr_bytes)?;
+    if ehdr.e_phentsize as usize != core::mem::size_of::<Phdr>() {
+        return Err(Error::BadProgramHeaders);
+    }
+    let phoff = usize::try_from(ehdr.e_phoff).map_err(|_| Error::BadProgramHeaders)?;
+    let phnum = ehdr.e_phnum as usize;
+    let len = core::mem::size_of::<Phdr>().checked_mul(phnum).ok_or(Error::BadProgramHeaders)?;
+    let end = phoff.checked_add(len).ok_or(Error::BadProgramHeaders)?;
+    if end > bytes.len() {
+        return Err(Error::BadProgramHeaders);
+    }
+    Ok(unsafe { core::slice::from_raw_parts(bytes[phoff..].as_ptr().cast::<Phdr>(), phnum) })
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+/// Walk the note entries of a `PT_NOTE` segment looking for
+/// `NT_GNU_BUILD_ID` in the `"GNU\0"` namespace.
+fn note_build_id(bytes: &[u8], phdr: &Phdr) -> Option<[u8; BUILD_ID_LEN]> {
+    let start = usize::try_from(phdr.p_offset).ok()?;
+    let len = usize::try_from(phdr.p_filesz).ok()?;
+    let note = bytes.get(start..start.checked_add(len)?)?;
+
+    let mut offset = 0;
+    while offset + 12 <= note.len() {
+        let namesz = u32::from_ne_bytes(note[offset..offset + 4].try_into().ok()?) as usize;
+        let descsz = u32::from_ne_bytes(note[offset + 4..offset + 8].try_into().ok()?) as usize;
+        let n_type = u32::from_ne_bytes(note[offset + 8..offset + 12].try_into().ok()?);
+        offset += 12;
+
+        let name_end = offset.checked_add(namesz)?;
+        let name = note.get(offset..name_end)?;
+        offset = align4(name_end);
+
+        let desc_end = offset.checked_add(descsz)?;
+        let desc = note.get(offset..desc_end)?;
+        offset = align4(desc_end);
+
+        if name == b"GNU\0" && n_type == NT_GNU_BUILD_ID && descsz == BUILD_ID_LEN {
+            let mut id = [0u8; BUILD_ID_LEN];
+            id.copy_from_slice(desc);
+            return Some(id);
+        }
+    }
+    None
+}
+
+/// Extract the `.note.gnu.build-id` descriptor from an ELF64 image,
+/// without mapping any of its segments.
+pub(crate) fn build_id(bytes: &[u8]) -> Result<Option<[u8; BUILD_ID_LEN]>, Error> {
+    let phdrs = program_headers(bytes, bytes)?;
+    Ok(phdrs.iter().filter(|phdr| phdr.p_type == PT_NOTE).find_map(|phdr| note_build_id(bytes, phdr)))
+}
+
+/// Map the `PT_LOAD` segments of `bytes`, an ELF64 image, into place
+/// and return its entry point and segment list.
+///
+/// `dest_limit` bounds the destination range: every segment's
+/// `[p_vaddr, p_vaddr + p_memsz)` must fall within `0..dest_limit`
+/// (the caller's known-good physical memory, e.g. the highest address
+/// covered by the Multiboot memory map), and segments must not overlap
+/// one another. A module with a bogus or colliding `p_vaddr`/`p_memsz`
+/// is rejected with [`Error::SegmentOutOfBounds`] rather than silently
+/// copied over unrelated memory.
+///
+/// # Safety
+///
+/// `bytes` must be the sole owner of the physical range backing each
+/// segment's destination; this copies into `theon::VZERO`-relative
+/// memory without any synchronization.
+pub(crate) unsafe fn load(bytes: &[u8], dest_limit: u64) -> Result<Loaded, Error> {
+    let ehdr = read_ehdr(bytes)?;
+    let phdrs = program_headers(bytes, bytes)?;
+
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut dynamic = None;
+    let mut build_id = None;
+    for phdr in phdrs {
+        if phdr.p_type == PT_DYNAMIC {
+            dynamic = Some((phdr.p_vaddr, phdr.p_memsz));
+        }
+        if phdr.p_type == PT_NOTE && build_id.is_none() {
+            build_id = note_build_id(bytes, phdr);
+        }
+        if phdr.p_type != PT_LOAD {
+            continue;
+        }
+
+        let file_start = usize::try_from(phdr.p_offset).map_err(|_| Error::SegmentOutOfBounds)?;
+        let file_len = usize::try_from(phdr.p_filesz).map_err(|_| Error::SegmentOutOfBounds)?;
+        let file_end = file_start.checked_add(file_len).ok_or(Error::SegmentOutOfBounds)?;
+        let src = bytes.get(file_start..file_end).ok_or(Error::SegmentOutOfBounds)?;
+
+        if phdr.p_memsz < phdr.p_filesz {
+            return Err(Error::SegmentOutOfBounds);
+        }
+        let bss_len = (phdr.p_memsz - phdr.p_filesz) as usize;
+
+        let dest_end = phdr.p_vaddr.checked_add(phdr.p_memsz).ok_or(Error::SegmentOutOfBounds)?;
+        if dest_end > dest_limit {
+            return Err(Error::SegmentOutOfBounds);
+        }
+        if segments.iter().any(|s| phdr.p_vaddr < s.vaddr + s.memsz && s.vaddr < dest_end) {
+            return Err(Error::SegmentOutOfBounds);
+        }
+
+        unsafe {
+            let dst = theon::VZERO.add(phdr.p_vaddr as usize).cast_mut();
+            core::ptr::copy_nonoverlapping(src.as_ptr(), dst, src.len());
+            core::ptr::write_bytes(dst.add(src.len()), 0, bss_len);
+        }
+
+        segments.push(Segment {
+            vaddr: phdr.p_vaddr,
+            memsz: phdr.p_memsz,
+            perms: Permissions::from_flags(phdr.p_flags),
+        });
+    }
+
+    Ok(Loaded { entry: ehdr.e_entry, segments, dynamic, build_id })
+}
+
+#[cfg(test)]
+const EHDR_SIZE: usize = core::mem::size_of::<Ehdr>();
+#[cfg(test)]
+const PHDR_SIZE: usize = core::mem::size_of::<Phdr>();
+
+/// Build a minimal well-formed ELF64 image with a single program header
+/// of `p_type`/`p_flags`/`p_filesz`, whose payload is `data`, placed
+/// right after the program header table.
+///
+/// Shared by this module's tests and [`super::multiboot1`]'s, so the two
+/// don't maintain separate copies of the same byte-for-byte layout.
+#[cfg(test)]
+pub(crate) fn build_elf(p_type: u32, p_flags: u32, data: &[u8]) -> Vec<u8> {
+    let data_off = EHDR_SIZE + PHDR_SIZE;
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&ELFMAG);
+    bytes.push(ELFCLASS64);
+    bytes.push(ELFDATA2LSB);
+    bytes.extend_from_slice(&[0u8; 10]); // rest of e_ident
+    bytes.extend_from_slice(&0u16.to_ne_bytes()); // e_type
+    bytes.extend_from_slice(&EM_X86_64.to_ne_bytes()); // e_machine
+    bytes.extend_from_slice(&1u32.to_ne_bytes()); // e_version
+    bytes.extend_from_slice(&0u64.to_ne_bytes()); // e_entry
+    bytes.extend_from_slice(&(EHDR_SIZE as u64).to_ne_bytes()); // e_phoff
+    bytes.extend_from_slice(&0u64.to_ne_bytes()); // e_shoff
+    bytes.extend_from_slice(&0u32.to_ne_bytes()); // e_flags
+    bytes.extend_from_slice(&(EHDR_SIZE as u16).to_ne_bytes()); // e_ehsize
+    bytes.extend_from_slice(&(PHDR_SIZE as u16).to_ne_bytes()); // e_phentsize
+    bytes.extend_from_slice(&1u16.to_ne_bytes()); // e_phnum
+    bytes.extend_from_slice(&0u16.to_ne_bytes()); // e_shentsize
+    bytes.extend_from_slice(&0u16.to_ne_bytes()); // e_shnum
+    bytes.extend_from_slice(&0u16.to_ne_bytes()); // e_shstrndx
+    assert_eq!(bytes.len(), EHDR_SIZE);
+
+    bytes.extend_from_slice(&p_type.to_ne_bytes());
+    bytes.extend_from_slice(&p_flags.to_ne_bytes());
+    bytes.extend_from_slice(&(data_off as u64).to_ne_bytes()); // p_offset
+    bytes.extend_from_slice(&0u64.to_ne_bytes()); // p_vaddr
+    bytes.extend_from_slice(&0u64.to_ne_bytes()); // p_paddr
+    bytes.extend_from_slice(&(data.len() as u64).to_ne_bytes()); // p_filesz
+    bytes.extend_from_slice(&(data.len() as u64).to_ne_bytes()); // p_memsz
+    bytes.extend_from_slice(&4u64.to_ne_bytes()); // p_align
+    assert_eq!(bytes.len(), data_off);
+
+    bytes.extend_from_slice(data);
+    bytes
+}
+
+/// Build a `.note.gnu.build-id` descriptor (the `"GNU\0"`-namespace,
+/// `NT_GNU_BUILD_ID`-typed note) holding `id`, ready to embed as the
+/// payload of a `PT_NOTE` segment built by [`build_elf`].
+#[cfg(test)]
+pub(crate) fn gnu_build_id_note(id: &[u8; BUILD_ID_LEN]) -> Vec<u8> {
+    let mut note = Vec::new();
+    note.extend_from_slice(&4u32.to_ne_bytes()); // namesz
+    note.extend_from_slice(&(BUILD_ID_LEN as u32).to_ne_bytes()); // descsz
+    note.extend_from_slice(&NT_GNU_BUILD_ID.to_ne_bytes()); // type
+    note.extend_from_slice(b"GNU\0");
+    note.extend_from_slice(id);
+    note
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_id_absent_without_a_note_segment() {
+        let bytes = build_elf(PT_LOAD, PF_R, &[]);
+        assert_eq!(build_id(&bytes), Ok(None));
+    }
+
+    #[test]
+    fn build_id_extracted_from_note_segment() {
+        let id = [0x42u8; BUILD_ID_LEN];
+        let bytes = build_elf(PT_NOTE, 0, &gnu_build_id_note(&id));
+        assert_eq!(build_id(&bytes), Ok(Some(id)));
+    }
+
+    #[test]
+    fn build_id_too_short_image_is_an_error() {
+        assert_eq!(build_id(&[0u8; 4]), Err(Error::TooShort));
+    }
+}
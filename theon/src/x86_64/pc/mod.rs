@@ -0,0 +1,95 @@
+// Copyright 2021  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+mod dynamic;
+mod elf;
+mod multiboot1;
+mod multiboot2;
+
+use crate::x86_64::memory;
+use core::cell::SyncUnsafeCell;
+
+pub(crate) use dynamic::{link_modules, Linked, LinkError};
+pub(crate) use multiboot1::{BuildIdTable, InitInfo, Multiboot1, MultibootModule};
+pub(crate) use multiboot2::Multiboot2;
+
+const MULTIBOOT1_MAGIC: u32 = 0x2BAD_B002;
+const MULTIBOOT2_MAGIC: u32 = 0x36D7_6289;
+
+/// The physical frame allocator backing `MM::allocate`/`MM::deallocate`
+/// in [`multiboot1`]. Installed once, from [`Multiboot::info_verified`],
+/// covering both boot protocols since it is populated from the unified
+/// [`InitInfo::regions`] rather than from either `Multiboot1` or
+/// `Multiboot2` directly; `None` beforehand.
+pub(super) static FRAME_ALLOCATOR: SyncUnsafeCell<Option<memory::FrameAllocator>> = SyncUnsafeCell::new(None);
+
+fn install_frame_allocator(regions: &[memory::Region]) {
+    if unsafe { (*FRAME_ALLOCATOR.get()).is_some() } {
+        return;
+    }
+    let mut allocator = memory::FrameAllocator::new(regions);
+    for region in regions.iter().filter(|r| matches!(r.typ, memory::Type::Loader | memory::Type::Module)) {
+        allocator.reserve(region);
+    }
+    unsafe {
+        *FRAME_ALLOCATOR.get() = Some(allocator);
+    }
+}
+
+/// Reserve the physical range backing each segment of `loaded` so the
+/// frame allocator never hands it out, the same way the as-delivered
+/// `Module` region is reserved at boot. Call this once a module has
+/// actually been mapped by [`multiboot1::MultibootModule::load`].
+pub(super) fn reserve_loaded(loaded: &elf::Loaded) {
+    let Some(allocator) = (unsafe { (*FRAME_ALLOCATOR.get()).as_mut() }) else { return };
+    for segment in &loaded.segments {
+        let region = memory::Region {
+            start: segment.vaddr,
+            end: segment.vaddr + segment.memsz,
+            typ: memory::Type::Module,
+        };
+        allocator.reserve(&region);
+    }
+}
+
+/// Either boot-information format, normalized behind a single
+/// [`InitInfo`]-producing interface.
+pub(crate) enum Multiboot {
+    V1(Multiboot1),
+    V2(Multiboot2),
+}
+
+impl Multiboot {
+    pub(crate) fn info(&self) -> InitInfo<'_> {
+        self.info_verified(&[])
+    }
+
+    /// Like [`Multiboot::info`], but refusing to register any module
+    /// whose name appears in `expected_build_ids` without a matching
+    /// `.note.gnu.build-id`, regardless of which protocol booted the
+    /// machine.
+    pub(crate) fn info_verified(&self, expected_build_ids: BuildIdTable<'_>) -> InitInfo<'_> {
+        let info = match self {
+            Multiboot::V1(mb) => mb.info_verified(expected_build_ids),
+            Multiboot::V2(mb) => mb.info_verified(expected_build_ids),
+        };
+        install_frame_allocator(&info.regions);
+        info
+    }
+}
+
+/// Parse the boot-information block at `mbinfo_phys`, choosing the
+/// Multiboot1 or Multiboot2 parser according to the handoff `magic`
+/// value passed to `start`.
+pub(crate) fn init(magic: u32, mbinfo_phys: u64) -> Multiboot {
+    uart::panic_println!("mbinfo: {:08x} (magic {:08x})", mbinfo_phys, magic);
+    match magic {
+        MULTIBOOT1_MAGIC => Multiboot::V1(Multiboot1::new(mbinfo_phys)),
+        MULTIBOOT2_MAGIC => Multiboot::V2(Multiboot2::new(mbinfo_phys)),
+        _ => panic!("unrecognized multiboot magic: {:08x}", magic),
+    }
+}
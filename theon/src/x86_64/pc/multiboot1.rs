@@ -5,13 +5,14 @@
 // license that can be found in the LICENSE file or at
 // https://opensource.org/licenses/MIT.
 
+use super::elf;
 use crate::theon;
 use crate::x86_64::memory;
 use alloc::vec::Vec;
 use core::cell::SyncUnsafeCell;
 use multiboot::information::{MemoryManagement, MemoryType, Multiboot, PAddr};
 
-unsafe fn phys_to_slice(phys_addr: PAddr, len: usize) -> Option<&'static [u8]> {
+pub(super) unsafe fn phys_to_slice(phys_addr: PAddr, len: usize) -> Option<&'static [u8]> {
     Some(unsafe {
         let p = theon::VZERO.add(phys_addr as usize);
         core::slice::from_raw_parts(p, len)
@@ -25,13 +26,23 @@ impl MemoryManagement for MM {
         unsafe { phys_to_slice(phys_addr, len) }
     }
 
-    unsafe fn allocate(&mut self, _len: usize) -> Option<(PAddr, &mut [u8])> {
-        None
+    unsafe fn allocate(&mut self, len: usize) -> Option<(PAddr, &mut [u8])> {
+        // Shared with the Multiboot2 path: populated by
+        // `super::install_frame_allocator` once `InitInfo::regions` is
+        // known, regardless of which protocol booted the machine.
+        let allocator = unsafe { (*super::FRAME_ALLOCATOR.get()).as_mut() }?;
+        let frames = (len as u64).div_ceil(memory::FRAME_SIZE) as usize;
+        let addr = allocator.alloc_contiguous(frames)?;
+        let slice = unsafe { phys_to_slice(addr, len)?.as_ptr().cast_mut() };
+        Some((addr as PAddr, unsafe { core::slice::from_raw_parts_mut(slice, len) }))
     }
 
     unsafe fn deallocate(&mut self, addr: PAddr) {
-        if addr != 0 {
-            unimplemented!();
+        if addr == 0 {
+            return;
+        }
+        if let Some(allocator) = unsafe { (*super::FRAME_ALLOCATOR.get()).as_mut() } {
+            allocator.free_frame(addr as u64).ok();
         }
     }
 }
@@ -71,6 +82,15 @@ impl MultibootModule<'_> {
         let phys_end = phys_start.wrapping_add(self.bytes.len());
         memory::Region { start: phys_start as u64, end: phys_end as u64, typ: memory::Type::Module }
     }
+
+    /// Load this module as an ELF64 image, mapping its `PT_LOAD`
+    /// segments into place and returning its entry point.
+    ///
+    /// `dest_limit` bounds where a segment may be mapped; see
+    /// [`elf::load`].
+    pub(crate) fn load(&self, dest_limit: u64) -> Result<elf::Loaded, elf::Error> {
+        unsafe { elf::load(self.bytes, dest_limit) }
+    }
 }
 
 fn parse_modules<'a>(mb: &'a Multiboot<'_, '_>) -> Option<Vec<MultibootModule<'a>>> {
@@ -90,6 +110,15 @@ pub(crate) struct InitInfo<'a> {
     pub modules: Vec<MultibootModule<'a>>,
 }
 
+impl<'a> InitInfo<'a> {
+    /// Load every module and bind any `DT_NEEDED` dependencies between
+    /// them, in dependency order.
+    pub(crate) fn link_modules(&self) -> Result<Vec<super::dynamic::Linked<'a>>, super::dynamic::LinkError> {
+        let dest_limit = self.memory_regions.iter().map(|r| r.end).max().unwrap_or(0);
+        super::dynamic::link_modules(&self.modules, dest_limit)
+    }
+}
+
 pub(crate) struct Multiboot1 {
     multiboot: Multiboot<'static, 'static>,
 }
@@ -105,26 +134,50 @@ impl Multiboot1 {
     }
 
     pub(crate) fn info(&self) -> InitInfo<'_> {
-        let (memory_regions, regions, modules) = init_memory_regions(&self.multiboot);
+        self.info_verified(&[])
+    }
+
+    /// Like [`Multiboot1::info`], but refusing to register any module
+    /// whose name appears in `expected_build_ids` without a matching
+    /// `.note.gnu.build-id`.
+    ///
+    /// Note that the frame allocator backing `MM::allocate`/`deallocate`
+    /// is installed by [`super::Multiboot::info_verified`], not here, so
+    /// it covers the Multiboot2 path as well.
+    pub(crate) fn info_verified(&self, expected_build_ids: BuildIdTable<'_>) -> InitInfo<'_> {
+        let (memory_regions, regions, modules) = init_memory_regions(&self.multiboot, expected_build_ids);
         InitInfo { memory_regions, regions, modules }
     }
 }
 
-pub(crate) fn init(mbinfo_phys: u64) -> Multiboot1 {
-    uart::panic_println!("mbinfo: {:08x}", mbinfo_phys);
-    Multiboot1::new(mbinfo_phys)
+/// Expected build-ids, keyed by module name, used to reject tampered
+/// or stale boot modules before they are registered.
+pub(crate) type BuildIdTable<'a> = &'a [(&'a str, [u8; elf::BUILD_ID_LEN])];
+
+pub(super) fn verify_build_id(module: &MultibootModule<'_>, expected_build_ids: BuildIdTable<'_>) -> bool {
+    let Some(name) = module.name else { return true };
+    let Some((_, want)) = expected_build_ids.iter().find(|(n, _)| *n == name) else { return true };
+    let got = elf::build_id(module.bytes).ok().flatten();
+    if got.as_ref() == Some(want) {
+        true
+    } else {
+        uart::panic_println!("module {}: build-id mismatch, refusing to register", name);
+        false
+    }
 }
 
 fn init_memory_regions<'a>(
     mb: &'a Multiboot<'_, '_>,
+    expected_build_ids: BuildIdTable<'_>,
 ) -> (Vec<memory::Region>, Vec<memory::Region>, Vec<MultibootModule<'a>>) {
     let memory_regions = parse_memory(mb).unwrap();
-    let modules = parse_modules(mb).expect("could not find modules");
+    let mut modules = parse_modules(mb).expect("could not find modules");
+    modules.retain(|module| verify_build_id(module, expected_build_ids));
     let regions = usable_regions(memory_regions.clone(), &modules);
     (memory_regions, regions, modules)
 }
 
-fn usable_regions(
+pub(super) fn usable_regions(
     mut regions: Vec<memory::Region>,
     modules: &[MultibootModule<'_>],
 ) -> Vec<memory::Region> {
@@ -157,3 +210,42 @@ fn fix_overlap(mut overlapping_regions: Vec<memory::Region>) -> Vec<memory::Regi
     regions.push(prev);
     regions
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A minimal well-formed ELF64 image with a single `PT_NOTE` segment
+    /// holding a `.note.gnu.build-id` descriptor of `id`.
+    fn elf_with_build_id(id: &[u8; elf::BUILD_ID_LEN]) -> Vec<u8> {
+        elf::build_elf(elf::PT_NOTE, 0, &elf::gnu_build_id_note(id))
+    }
+
+    #[test]
+    fn verify_build_id_passes_modules_with_no_name() {
+        let module = MultibootModule { bytes: &[], name: None };
+        assert!(verify_build_id(&module, &[("anything", [0u8; elf::BUILD_ID_LEN])]));
+    }
+
+    #[test]
+    fn verify_build_id_passes_names_not_in_table() {
+        let bytes = elf_with_build_id(&[0x11; elf::BUILD_ID_LEN]);
+        let module = MultibootModule { bytes: &bytes, name: Some("init") };
+        assert!(verify_build_id(&module, &[("other", [0x22; elf::BUILD_ID_LEN])]));
+    }
+
+    #[test]
+    fn verify_build_id_accepts_a_matching_build_id() {
+        let id = [0x33; elf::BUILD_ID_LEN];
+        let bytes = elf_with_build_id(&id);
+        let module = MultibootModule { bytes: &bytes, name: Some("init") };
+        assert!(verify_build_id(&module, &[("init", id)]));
+    }
+
+    #[test]
+    fn verify_build_id_rejects_a_mismatched_build_id() {
+        let bytes = elf_with_build_id(&[0x44; elf::BUILD_ID_LEN]);
+        let module = MultibootModule { bytes: &bytes, name: Some("init") };
+        assert!(!verify_build_id(&module, &[("init", [0x55; elf::BUILD_ID_LEN])]));
+    }
+}
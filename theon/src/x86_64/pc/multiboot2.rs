@@ -0,0 +1,190 @@
+// Copyright 2021  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Multiboot2 boot-information parsing.
+//!
+//! Mirrors [`super::multiboot1::Multiboot1`], but for the tag-based
+//! information format handed off by Multiboot2-compliant bootloaders:
+//! a small fixed header followed by a sequence of 8-byte-aligned tags,
+//! each beginning with a `u32` type and `u32` size, terminated by a
+//! type-0 tag. Everything gathered here is normalized into the same
+//! [`InitInfo`]/[`memory::Region`]/[`MultibootModule`] representation
+//! Multiboot1 produces, so the rest of `init` doesn't care which
+//! protocol booted the machine.
+
+use super::multiboot1::{self, InitInfo, MultibootModule};
+use crate::theon;
+use crate::x86_64::memory;
+use alloc::vec::Vec;
+
+const TAG_END: u32 = 0;
+const TAG_MODULE: u32 = 3;
+const TAG_MEMORY_MAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+const TAG_ACPI_OLD: u32 = 14;
+const TAG_ACPI_NEW: u32 = 15;
+
+const MEMORY_AVAILABLE: u32 = 1;
+const MEMORY_ACPI_RECLAIMABLE: u32 = 3;
+const MEMORY_NVS: u32 = 4;
+const MEMORY_DEFECTIVE: u32 = 5;
+
+fn align8(n: usize) -> usize {
+    (n + 7) & !7
+}
+
+/// A framebuffer tag, kept around for the console driver to consume.
+pub(crate) struct Framebuffer {
+    pub(crate) addr: u64,
+    pub(crate) pitch: u32,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) bpp: u8,
+}
+
+/// The raw ACPI RSDP bytes, old- or new-style, as handed to us by the
+/// bootloader.
+pub(crate) struct Acpi {
+    pub(crate) rsdp: Vec<u8>,
+}
+
+struct Tags {
+    memory_regions: Vec<memory::Region>,
+    modules: Vec<MultibootModule<'static>>,
+    framebuffer: Option<Framebuffer>,
+    acpi: Option<Acpi>,
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_ne_bytes(bytes.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+fn module_tag(tag: &[u8]) -> Option<MultibootModule<'static>> {
+    let mod_start = read_u32(tag, 8)?;
+    let mod_end = read_u32(tag, 12)?;
+    let name_bytes = tag.get(16..)?;
+    let name_len = name_bytes.iter().position(|&b| b == 0).unwrap_or(name_bytes.len());
+    let name = core::str::from_utf8(&name_bytes[..name_len]).ok();
+    let bytes = unsafe { multiboot1::phys_to_slice(mod_start as u64, mod_end.saturating_sub(mod_start) as usize)? };
+    Some(MultibootModule { bytes, name: name.map(|n| n.split('/').next_back().unwrap()) })
+}
+
+fn memory_map_tag(tag: &[u8]) -> Option<Vec<memory::Region>> {
+    let entry_size = read_u32(tag, 8)? as usize;
+    if entry_size == 0 {
+        return None;
+    }
+    let mut regions = Vec::new();
+    let mut offset = 16;
+    while offset + entry_size <= tag.len() {
+        let base = read_u64(tag, offset)?;
+        let length = read_u64(tag, offset + 8)?;
+        let typ = read_u32(tag, offset + 16)?;
+        regions.push(memory::Region {
+            start: base,
+            end: base.wrapping_add(length),
+            typ: match typ {
+                MEMORY_AVAILABLE => memory::Type::RAM,
+                MEMORY_ACPI_RECLAIMABLE => memory::Type::ACPI,
+                MEMORY_NVS => memory::Type::NonVolatile,
+                MEMORY_DEFECTIVE => memory::Type::Defective,
+                _ => memory::Type::Reserved,
+            },
+        });
+        offset += entry_size;
+    }
+    Some(regions)
+}
+
+fn framebuffer_tag(tag: &[u8]) -> Option<Framebuffer> {
+    Some(Framebuffer {
+        addr: read_u64(tag, 8)?,
+        pitch: read_u32(tag, 16)?,
+        width: read_u32(tag, 20)?,
+        height: read_u32(tag, 24)?,
+        bpp: *tag.get(28)?,
+    })
+}
+
+fn parse_tags(info: &'static [u8]) -> Tags {
+    let mut memory_regions = Vec::new();
+    let mut modules = Vec::new();
+    let mut framebuffer = None;
+    let mut acpi = None;
+
+    let mut offset = 8;
+    while offset + 8 <= info.len() {
+        let Some(typ) = read_u32(info, offset) else { break };
+        let Some(size) = read_u32(info, offset + 4) else { break };
+        if typ == TAG_END {
+            break;
+        }
+        let size = size as usize;
+        let Some(tag) = info.get(offset..offset + size) else { break };
+
+        match typ {
+            TAG_MODULE => {
+                if let Some(module) = module_tag(tag) {
+                    modules.push(module);
+                }
+            }
+            TAG_MEMORY_MAP => {
+                if let Some(regions) = memory_map_tag(tag) {
+                    memory_regions = regions;
+                }
+            }
+            TAG_FRAMEBUFFER => framebuffer = framebuffer_tag(tag),
+            TAG_ACPI_OLD | TAG_ACPI_NEW => acpi = Some(Acpi { rsdp: tag.get(8..).unwrap_or(&[]).to_vec() }),
+            _ => {}
+        }
+
+        offset = align8(offset + size);
+    }
+
+    Tags { memory_regions, modules, framebuffer, acpi }
+}
+
+pub(crate) struct Multiboot2 {
+    info: &'static [u8],
+}
+
+impl Multiboot2 {
+    pub(crate) fn new(mbinfo_phys: u64) -> Multiboot2 {
+        let info = unsafe {
+            let ptr = theon::VZERO.add(mbinfo_phys as usize);
+            let total_size = read_u32(core::slice::from_raw_parts(ptr, 8), 0).unwrap() as usize;
+            core::slice::from_raw_parts(ptr, total_size)
+        };
+        Multiboot2 { info }
+    }
+
+    pub(crate) fn info(&self) -> InitInfo<'static> {
+        self.info_verified(&[])
+    }
+
+    /// Like [`Multiboot2::info`], but refusing to register any module
+    /// whose name appears in `expected_build_ids` without a matching
+    /// `.note.gnu.build-id`.
+    pub(crate) fn info_verified(&self, expected_build_ids: multiboot1::BuildIdTable<'_>) -> InitInfo<'static> {
+        let mut tags = parse_tags(self.info);
+        tags.modules.retain(|module| multiboot1::verify_build_id(module, expected_build_ids));
+        let regions = multiboot1::usable_regions(tags.memory_regions.clone(), &tags.modules);
+        InitInfo { memory_regions: tags.memory_regions, regions, modules: tags.modules }
+    }
+
+    pub(crate) fn framebuffer(&self) -> Option<Framebuffer> {
+        parse_tags(self.info).framebuffer
+    }
+
+    pub(crate) fn acpi(&self) -> Option<Acpi> {
+        parse_tags(self.info).acpi
+    }
+}